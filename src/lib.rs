@@ -5,9 +5,15 @@
 #[macro_use] extern crate log;
 
 use std::any::Any;
+use std::backtrace::Backtrace as StdBacktrace;
+use std::cell::RefCell;
+use std::env;
 use std::fmt;
 use std::marker::PhantomData;
 use std::mem;
+use std::panic::Location;
+use std::sync::OnceLock;
+use std::sync::RwLock;
 
 /// Trait for objects that can accept warnings.
 pub trait Warn<W> {
@@ -43,6 +49,12 @@ impl<W: Any+fmt::Debug+Send> Warn<W> for Panic {
     }
 }
 
+impl<W: Any+fmt::Debug+Send> Warn<Located<W>> for Panic {
+    fn warn(&mut self, warning: Located<W>) {
+        panic!("{}: {:?}", warning.location, warning.warning);
+    }
+}
+
 /// Struct that logs each warning it encounters.
 ///
 /// Logging is done via the `log` crate.
@@ -55,6 +67,202 @@ impl<W: fmt::Debug> Warn<W> for Log {
     }
 }
 
+impl<W: fmt::Debug> Warn<Located<W>> for Log {
+    fn warn(&mut self, warning: Located<W>) {
+        warn!("{}: {:?}", warning.location, warning.warning);
+    }
+}
+
+static HOOK: RwLock<Option<Box<dyn Fn(&dyn Any) + Send + Sync>>> = RwLock::new(None);
+
+fn default_hook(warning: &dyn Any) {
+    let _ = warning;
+}
+
+/// Installs a new global warning hook, to be called by `Hook` whenever no
+/// `&mut dyn Warn<W>` was threaded through to the point where the warning
+/// occurred.
+///
+/// The hook is `Fn` rather than `FnMut`, like a panic hook, so that `Hook`
+/// only ever needs a read lock to call it: that lets warnings from
+/// different threads be dispatched concurrently instead of serializing on
+/// a write lock or racing to check the hook back in. A warning sent
+/// through `Hook` from inside the hook itself takes a second read lock on
+/// the same thread, which `std::sync::RwLock` does not guarantee to grant:
+/// it can deadlock or panic.
+///
+/// This mirrors `std::panic::set_hook`.
+pub fn set_hook(hook: Box<dyn Fn(&dyn Any) + Send + Sync>) {
+    *HOOK.write().unwrap() = Some(hook);
+}
+
+/// Removes the global warning hook, returning the previously installed one.
+///
+/// If no hook was installed, a no-op hook is returned. Note that this is not
+/// quite the same as having no hook installed at all: `Hook::warn` falls
+/// back to logging via `warn!` when no hook has been set, so
+/// `set_hook(take_hook())` silently turns "no hook installed" into "hook
+/// installed that discards everything" if no hook was installed to begin
+/// with.
+///
+/// This mirrors `std::panic::take_hook`.
+pub fn take_hook() -> Box<dyn Fn(&dyn Any) + Send + Sync> {
+    HOOK.write().unwrap().take().unwrap_or_else(|| Box::new(default_hook))
+}
+
+/// Struct that dispatches each warning to the globally installed hook.
+///
+/// This allows a library to bury a `Warn` parameter deep in its API while
+/// the top-level application configures handling once, globally, via
+/// `set_hook`, the same way panics are handled via a panic hook.
+#[derive(Clone, Copy, Debug, Eq, Ord, Hash, PartialEq, PartialOrd)]
+pub struct Hook;
+
+impl<W: Any+fmt::Debug+Send> Warn<W> for Hook {
+    fn warn(&mut self, warning: W) {
+        let hook = HOOK.read().unwrap();
+        match hook.as_ref() {
+            Some(hook) => hook(&warning),
+            None => {
+                drop(hook);
+                warn!("{:?}", warning);
+            }
+        }
+    }
+}
+
+thread_local! {
+    static LOCAL: RefCell<Option<Box<dyn Any>>> = RefCell::new(None);
+}
+
+/// Installs a thread-local sink for the duration of `f`, returning `f`'s
+/// result together with every warning that `Captured` collected while it
+/// ran.
+///
+/// Nested calls are supported: the sink that was active before `capture`
+/// was called is restored once `f` returns, even if it unwinds.
+///
+/// Modeled on the thread-local stderr capture behind
+/// `std::io::set_output_capture`.
+pub fn capture<W: Any, R, F: FnOnce() -> R>(f: F) -> (R, Vec<W>) {
+    struct Restore(Option<Box<dyn Any>>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            LOCAL.with(|cell| *cell.borrow_mut() = self.0.take());
+        }
+    }
+
+    let previous = LOCAL.with(|cell| cell.replace(Some(Box::new(Vec::<W>::new()) as Box<dyn Any>)));
+    let _restore = Restore(previous);
+    let result = f();
+    let warnings = LOCAL.with(|cell| cell.borrow_mut().take())
+        .and_then(|warnings| warnings.downcast::<Vec<W>>().ok())
+        .map_or_else(Vec::new, |warnings| *warnings);
+    (result, warnings)
+}
+
+/// Struct that pushes each warning it encounters into the thread-local
+/// vector installed by `capture`, silently discarding it if no `capture`
+/// call is currently active on this thread, or if the innermost active
+/// `capture` call collects a different warning type.
+#[derive(Clone, Copy, Debug, Eq, Ord, Hash, PartialEq, PartialOrd)]
+pub struct Captured;
+
+impl<W: Any> Warn<W> for Captured {
+    fn warn(&mut self, warning: W) {
+        LOCAL.with(|cell| {
+            if let Some(vec) = cell.borrow_mut().as_mut().and_then(|w| w.downcast_mut::<Vec<W>>()) {
+                vec.push(warning);
+            }
+        });
+    }
+}
+
+fn backtrace_enabled() -> bool {
+    static ENABLED: OnceLock<bool> = OnceLock::new();
+    *ENABLED.get_or_init(|| {
+        env::var_os("WARN_BACKTRACE").map_or(false, |v| v == "1")
+    })
+}
+
+/// Helper struct for the `backtrace` function.
+pub struct Backtrace<'a, WT, W: Warn<WT> + 'a> {
+    warn: &'a mut W,
+    phantom: PhantomData<WT>,
+}
+
+/// Wraps a `Warn` sink so that, when the `WARN_BACKTRACE` environment
+/// variable is set to `1`, a backtrace is captured for each warning and
+/// logged via the `log` crate at `warn` level. The warning itself is
+/// forwarded to the underlying sink unchanged.
+///
+/// Whether backtraces are captured is decided once, the first time a
+/// warning is emitted, the same way the panic runtime caches its
+/// `RUST_BACKTRACE` check.
+pub fn backtrace<WT, W: Warn<WT>>(warn: &mut W) -> Backtrace<WT, W> {
+    Backtrace {
+        warn: warn,
+        phantom: PhantomData,
+    }
+}
+
+impl<'a, WT, W: Warn<WT>> Warn<WT> for Backtrace<'a, WT, W> {
+    fn warn(&mut self, warning: WT) {
+        if backtrace_enabled() {
+            warn!("warning triggered backtrace:\n{}", StdBacktrace::force_capture());
+        }
+        self.warn.warn(warning);
+    }
+}
+
+fn default_escalate() {
+    panic!("warning threshold exceeded");
+}
+
+/// Helper struct for the `threshold` function.
+pub struct Threshold<'a, WT, W: Warn<WT> + 'a, E: FnMut()> {
+    warn: &'a mut W,
+    max: usize,
+    count: usize,
+    escalated: bool,
+    escalate: E,
+    phantom: PhantomData<WT>,
+}
+
+/// Wraps a `Warn` sink so that, once at least `max` warnings have passed
+/// through, it escalates by panicking exactly once. `max == 0` escalates
+/// immediately, on the first warning.
+///
+/// Useful for fail-fast modes where a handful of warnings are tolerable but
+/// a flood indicates corrupt input that should abort processing.
+pub fn threshold<WT, W: Warn<WT>>(warn: &mut W, max: usize) -> Threshold<WT, W, fn()> {
+    threshold_with(warn, max, default_escalate)
+}
+
+/// Like `threshold`, but escalates by calling `escalate` instead of
+/// panicking.
+pub fn threshold_with<WT, W: Warn<WT>, E: FnMut()>(warn: &mut W, max: usize, escalate: E) -> Threshold<WT, W, E> {
+    Threshold {
+        warn: warn,
+        max: max,
+        count: 0,
+        escalated: false,
+        escalate: escalate,
+        phantom: PhantomData,
+    }
+}
+
+impl<'a, WT, W: Warn<WT>, E: FnMut()> Warn<WT> for Threshold<'a, WT, W, E> {
+    fn warn(&mut self, warning: WT) {
+        self.count += 1;
+        self.warn.warn(warning);
+        if !self.escalated && self.count >= self.max {
+            self.escalated = true;
+            (self.escalate)();
+        }
+    }
+}
+
 /// Helper struct for the `rev_map` function.
 pub struct RevMap<'a, WF, WT, W: Warn<WT> + 'a, F: FnMut(WF) -> WT> {
     warn: &'a mut W,
@@ -100,6 +308,51 @@ impl<WT, WF: Into<WT>, W: Warn<WT>> Warn<WF> for Wrap<WT, W> {
     }
 }
 
+/// A warning together with the source location it was raised at.
+pub struct Located<W> {
+    /// The warning.
+    pub warning: W,
+    /// The location the warning was raised at.
+    pub location: &'static Location<'static>,
+}
+
+/// Emits a warning to a `Warn<Located<W>>` sink, attaching the source
+/// location of this macro invocation.
+///
+/// `Warn::warn` itself can't be `#[track_caller]` and still report useful
+/// locations once called through a `&mut dyn Warn<W>`, so the location is
+/// captured here, at the call site, instead.
+#[macro_export]
+macro_rules! warn_at {
+    ($warn:expr, $warning:expr) => {
+        $crate::Warn::warn($crate::locate($warn), $warning)
+    };
+}
+
+/// Helper struct for the `locate` function.
+pub struct Locate<WT, W: Warn<Located<WT>>> {
+    warn: W,
+    phantom: PhantomData<WT>,
+}
+
+/// Wraps a `Warn<Located<W>>` struct so it can receive bare warnings of type
+/// `W`, attaching the caller's source location to each one.
+pub fn locate<WT, W: Warn<Located<WT>>>(warn: &mut W) -> &mut Locate<WT, W> {
+    unsafe {
+        mem::transmute(warn)
+    }
+}
+
+impl<WT, W: Warn<Located<WT>>> Warn<WT> for Locate<WT, W> {
+    #[track_caller]
+    fn warn(&mut self, warning: WT) {
+        self.warn.warn(Located {
+            warning: warning,
+            location: Location::caller(),
+        });
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::Ignore;
@@ -139,4 +392,151 @@ mod test {
     fn log() {
         Log.warn(WARNING);
     }
+
+    #[test]
+    #[should_panic(expected="unique_string")]
+    fn located_panic() {
+        warn_at!(&mut Panic, WARNING);
+    }
+
+    #[test]
+    fn located_vec() {
+        let mut vec = vec![];
+        warn_at!(&mut vec, WARNING);
+        assert_eq!(vec.len(), 1);
+        assert_eq!(vec[0].warning, WARNING);
+    }
+
+    #[test]
+    fn hook() {
+        use std::sync::Mutex;
+        use std::sync::Arc;
+        use super::Hook;
+        use super::set_hook;
+        use super::take_hook;
+
+        let seen = Arc::new(Mutex::new(None));
+        let seen2 = seen.clone();
+        set_hook(Box::new(move |warning| {
+            *seen2.lock().unwrap() = warning.downcast_ref::<&str>().cloned();
+        }));
+        Hook.warn(WARNING);
+        let _ = take_hook();
+        assert_eq!(*seen.lock().unwrap(), Some(WARNING));
+    }
+
+    #[test]
+    fn hook_concurrent() {
+        use std::sync::atomic::AtomicUsize;
+        use std::sync::atomic::Ordering;
+        use std::sync::Arc;
+        use std::thread;
+        use std::time::Duration;
+        use super::Hook;
+        use super::set_hook;
+        use super::take_hook;
+
+        let invocations = Arc::new(AtomicUsize::new(0));
+        let invocations2 = invocations.clone();
+        set_hook(Box::new(move |_| {
+            thread::sleep(Duration::from_millis(50));
+            invocations2.fetch_add(1, Ordering::SeqCst);
+        }));
+        let threads: Vec<_> = (0..8).map(|_| {
+            thread::spawn(|| Hook.warn(WARNING))
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let _ = take_hook();
+        assert_eq!(invocations.load(Ordering::SeqCst), 8);
+    }
+
+    #[test]
+    fn captured() {
+        use super::Captured;
+        use super::capture;
+
+        let (result, warnings) = capture::<&str, _, _>(|| {
+            Captured.warn(WARNING);
+            Captured.warn(WARNING2);
+            42
+        });
+        assert_eq!(result, 42);
+        assert_eq!(warnings, [WARNING, WARNING2]);
+    }
+
+    #[test]
+    fn captured_nested() {
+        use super::Captured;
+        use super::capture;
+
+        let (inner_warnings, outer_warnings) = capture::<&str, _, _>(|| {
+            Captured.warn(WARNING);
+            let (_, inner_warnings) = capture::<&str, _, _>(|| {
+                Captured.warn(WARNING2);
+            });
+            Captured.warn(WARNING);
+            inner_warnings
+        });
+        assert_eq!(outer_warnings, [WARNING, WARNING]);
+        assert_eq!(inner_warnings, [WARNING2]);
+    }
+
+    #[test]
+    fn captured_inactive() {
+        use super::Captured;
+
+        Captured.warn(WARNING);
+    }
+
+    #[test]
+    fn backtrace() {
+        use super::backtrace;
+
+        let mut vec = vec![];
+        backtrace(&mut vec).warn(WARNING);
+        assert_eq!(vec, [WARNING]);
+    }
+
+    #[test]
+    #[should_panic(expected="warning threshold exceeded")]
+    fn threshold() {
+        use super::threshold;
+
+        let mut vec = vec![];
+        let mut sink = threshold(&mut vec, 2);
+        sink.warn(WARNING);
+        sink.warn(WARNING);
+    }
+
+    #[test]
+    fn threshold_with() {
+        use super::threshold_with;
+        use std::cell::Cell;
+
+        let escalated = Cell::new(false);
+        let mut vec = vec![];
+        let mut sink = threshold_with(&mut vec, 2, || escalated.set(true));
+        sink.warn(WARNING);
+        assert!(!escalated.get());
+        sink.warn(WARNING);
+        assert!(escalated.get());
+        assert_eq!(vec, [WARNING, WARNING]);
+    }
+
+    #[test]
+    fn threshold_zero() {
+        use super::threshold_with;
+        use std::cell::Cell;
+
+        let escalations = Cell::new(0);
+        let mut vec = vec![];
+        let mut sink = threshold_with(&mut vec, 0, || escalations.set(escalations.get() + 1));
+        sink.warn(WARNING);
+        assert_eq!(escalations.get(), 1);
+        sink.warn(WARNING);
+        sink.warn(WARNING);
+        assert_eq!(escalations.get(), 1);
+    }
 }